@@ -1,18 +1,25 @@
+use std::borrow::Cow;
 use std::env;
 use std::fmt;
+use std::io::IsTerminal;
+use std::str::CharIndices;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::collections::BTreeSet;
 
 use term::is_a_terminal;
+use unicode_width::UnicodeWidthChar;
 
-fn supports_styling() -> bool {
+fn supports_styling(stream_is_tty: bool) -> bool {
     (&env::var("CLICOLOR").unwrap_or("0".into()) != "0" &&
-     is_a_terminal()) ||
+     stream_is_tty) ||
     &env::var("CLICOLOR_FORCE").unwrap_or("0".into()) != "0"
 }
 
 lazy_static! {
-    static ref ENABLE_STYLING: AtomicBool = AtomicBool::new(supports_styling());
+    static ref ENABLE_STYLING: AtomicBool = AtomicBool::new(supports_styling(is_a_terminal()));
+    static ref ENABLE_STDOUT_STYLING: AtomicBool =
+        AtomicBool::new(supports_styling(std::io::stdout().is_terminal()));
+    static ref ENABLE_STDERR_STYLING: AtomicBool =
+        AtomicBool::new(supports_styling(std::io::stderr().is_terminal()));
 }
 
 /// Returns if ANSI styles should be used.
@@ -35,6 +42,100 @@ pub fn set_should_style(val: bool) {
     ENABLE_STYLING.store(val, Ordering::Relaxed);
 }
 
+/// Returns if ANSI styles should be used for stdout.
+///
+/// This is identical to [`should_style`] but is based solely on whether
+/// stdout is a terminal, so it stays accurate when stdout and stderr are
+/// redirected independently of each other.
+pub fn should_style_stdout() -> bool {
+    ENABLE_STDOUT_STYLING.load(Ordering::Relaxed)
+}
+
+/// Override styling for stdout.
+pub fn set_should_style_stdout(val: bool) {
+    ENABLE_STDOUT_STYLING.store(val, Ordering::Relaxed);
+}
+
+/// Returns if ANSI styles should be used for stderr.
+///
+/// This is identical to [`should_style`] but is based solely on whether
+/// stderr is a terminal, so it stays accurate when stdout and stderr are
+/// redirected independently of each other.
+pub fn should_style_stderr() -> bool {
+    ENABLE_STDERR_STYLING.load(Ordering::Relaxed)
+}
+
+/// Override styling for stderr.
+pub fn set_should_style_stderr(val: bool) {
+    ENABLE_STDERR_STYLING.store(val, Ordering::Relaxed);
+}
+
+/// The stream a [`Styled`] value should consult when deciding whether to
+/// emit ANSI codes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stream {
+    /// Use the global, stdout-agnostic [`should_style`] flag.
+    Common,
+    /// Use [`should_style_stdout`].
+    Stdout,
+    /// Use [`should_style_stderr`].
+    Stderr,
+}
+
+impl Stream {
+    fn should_style(&self) -> bool {
+        match *self {
+            Stream::Common => should_style(),
+            Stream::Stdout => should_style_stdout(),
+            Stream::Stderr => should_style_stderr(),
+        }
+    }
+}
+
+fn supports_emoji() -> bool {
+    if cfg!(windows) {
+        env::var("WT_SESSION").is_ok() || env::var("ConEmuANSI").as_deref() == Ok("ON")
+    } else {
+        true
+    }
+}
+
+lazy_static! {
+    // Emoji are decorative output just like ANSI styling, so the default
+    // also honors `should_style`: a terminal that can't (or shouldn't)
+    // receive styling codes shouldn't get fancy glyphs either.
+    static ref WANTS_EMOJI: AtomicBool = AtomicBool::new(supports_emoji() && should_style());
+}
+
+/// Returns if emoji should be used instead of their ASCII fallback.
+///
+/// This defaults to `true` on platforms and terminals that generally
+/// render emoji correctly *and* that [`should_style`] has already deemed
+/// capable of decorative output, so a single policy governs both.
+pub fn wants_emoji() -> bool {
+    WANTS_EMOJI.load(Ordering::Relaxed)
+}
+
+/// Override whether emoji should be used.
+pub fn set_wants_emoji(val: bool) {
+    WANTS_EMOJI.store(val, Ordering::Relaxed);
+}
+
+/// Returns `fancy` if the terminal wants emoji, `fallback` otherwise.
+///
+/// Example:
+///
+/// ```rust,no_run
+/// let marker = emoji("✨", "*");
+/// ```
+pub fn emoji<'a>(fancy: &'a str, fallback: &'a str) -> &'a str {
+    if wants_emoji() {
+        fancy
+    } else {
+        fallback
+    }
+}
+
 /// An ANSI color.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Color {
@@ -46,6 +147,10 @@ pub enum Color {
     Magenta,
     Cyan,
     White,
+    /// A color from the 256-color palette.
+    Fixed(u8),
+    /// A 24-bit truecolor value.
+    Rgb(u8, u8, u8),
 }
 
 impl Color {
@@ -60,6 +165,25 @@ impl Color {
             Color::Magenta => 5,
             Color::Cyan => 6,
             Color::White => 7,
+            Color::Fixed(_) | Color::Rgb(..) => {
+                unreachable!("Fixed/Rgb colors do not use ansi_num")
+            }
+        }
+    }
+
+    fn fmt_fg(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Color::Fixed(n) => write!(f, "\x1b[38;5;{}m", n),
+            Color::Rgb(r, g, b) => write!(f, "\x1b[38;2;{};{};{}m", r, g, b),
+            _ => write!(f, "\x1b[{}m", self.ansi_num() + 30),
+        }
+    }
+
+    fn fmt_bg(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Color::Fixed(n) => write!(f, "\x1b[48;5;{}m", n),
+            Color::Rgb(r, g, b) => write!(f, "\x1b[48;2;{};{};{}m", r, g, b),
+            _ => write!(f, "\x1b[{}m", self.ansi_num() + 40),
         }
     }
 }
@@ -69,10 +193,12 @@ impl Color {
 pub enum Style {
     Bold,
     Dim,
+    Italic,
     Underlined,
     Blink,
     Reverse,
     Hidden,
+    Strikethrough,
 }
 
 impl Style {
@@ -81,20 +207,104 @@ impl Style {
         match *self {
             Style::Bold => 1,
             Style::Dim => 2,
+            Style::Italic => 3,
             Style::Underlined => 4,
             Style::Blink => 5,
             Style::Reverse => 7,
             Style::Hidden => 8,
+            Style::Strikethrough => 9,
+        }
+    }
+
+    /// The bit this style occupies in a [`StyleSet`].
+    #[inline(always)]
+    fn bit(&self) -> u16 {
+        1 << match *self {
+            Style::Bold => 0,
+            Style::Dim => 1,
+            Style::Italic => 2,
+            Style::Underlined => 3,
+            Style::Blink => 4,
+            Style::Reverse => 5,
+            Style::Hidden => 6,
+            Style::Strikethrough => 7,
+        }
+    }
+
+    #[inline(always)]
+    fn from_bit_index(idx: u32) -> Style {
+        match idx {
+            0 => Style::Bold,
+            1 => Style::Dim,
+            2 => Style::Italic,
+            3 => Style::Underlined,
+            4 => Style::Blink,
+            5 => Style::Reverse,
+            6 => Style::Hidden,
+            7 => Style::Strikethrough,
+            _ => unreachable!("invalid Style bit index"),
+        }
+    }
+}
+
+/// A packed, allocation-free set of [`Style`] attributes.
+///
+/// Each `Style` occupies a single bit, so the whole set fits in a `u16`
+/// and is cheap to copy.  Iteration order is the ascending bit order,
+/// which keeps SGR emission deterministic.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StyleSet(u16);
+
+impl StyleSet {
+    /// An empty set.
+    #[inline(always)]
+    pub fn new() -> StyleSet {
+        StyleSet(0)
+    }
+
+    /// Adds `style` to the set.
+    #[inline(always)]
+    pub fn set(&mut self, style: Style) {
+        self.0 |= style.bit();
+    }
+
+    /// Returns `true` if `style` is in the set.
+    #[inline(always)]
+    pub fn contains(&self, style: Style) -> bool {
+        self.0 & style.bit() != 0
+    }
+
+    /// Iterates over the contained styles in ascending bit order.
+    #[inline(always)]
+    pub fn iter(&self) -> StyleSetIter {
+        StyleSetIter(self.0)
+    }
+}
+
+/// Iterator over the styles contained in a [`StyleSet`].
+pub struct StyleSetIter(u16);
+
+impl Iterator for StyleSetIter {
+    type Item = Style;
+
+    fn next(&mut self) -> Option<Style> {
+        if self.0 == 0 {
+            return None;
         }
+        let idx = self.0.trailing_zeros();
+        self.0 &= !(1 << idx);
+        Some(Style::from_bit_index(idx))
     }
 }
 
 /// A formatting wrapper that can be styled for a terminal.
+#[derive(Copy, Clone)]
 pub struct Styled<D> {
     fg: Option<Color>,
     bg: Option<Color>,
-    styles: BTreeSet<Style>,
+    styles: StyleSet,
     force: Option<bool>,
+    stream: Stream,
     val: D,
 }
 
@@ -109,8 +319,9 @@ pub fn style<D>(val: D) -> Styled<D> {
     Styled {
         fg: None,
         bg: None,
-        styles: BTreeSet::new(),
+        styles: StyleSet::new(),
         force: None,
+        stream: Stream::Common,
         val: val,
     }
 }
@@ -123,6 +334,13 @@ impl<D> Styled<D> {
         self
     }
 
+    /// Targets a specific stream's styling flag instead of the common one.
+    #[inline(always)]
+    pub fn for_stream(mut self, stream: Stream) -> Styled<D> {
+        self.stream = stream;
+        self
+    }
+
     /// Sets a foreground color.
     #[inline(always)]
     pub fn fg(mut self, color: Color) -> Styled<D> {
@@ -140,7 +358,7 @@ impl<D> Styled<D> {
     /// Adds a style.
     #[inline(always)]
     pub fn style(mut self, style: Style) -> Styled<D> {
-        self.styles.insert(style);
+        self.styles.set(style);
         self
     }
 
@@ -160,12 +378,18 @@ impl<D> Styled<D> {
     #[inline(always)] pub fn on_magenta(self) -> Styled<D> { self.bg(Color::Magenta) }
     #[inline(always)] pub fn on_cyan(self) -> Styled<D> { self.bg(Color::Cyan) }
     #[inline(always)] pub fn on_white(self) -> Styled<D> { self.bg(Color::White) }
+    #[inline(always)] pub fn color256(self, color: u8) -> Styled<D> { self.fg(Color::Fixed(color)) }
+    #[inline(always)] pub fn on_color256(self, color: u8) -> Styled<D> { self.bg(Color::Fixed(color)) }
+    #[inline(always)] pub fn rgb(self, r: u8, g: u8, b: u8) -> Styled<D> { self.fg(Color::Rgb(r, g, b)) }
+    #[inline(always)] pub fn on_rgb(self, r: u8, g: u8, b: u8) -> Styled<D> { self.bg(Color::Rgb(r, g, b)) }
     #[inline(always)] pub fn bold(self) -> Styled<D> { self.style(Style::Bold) }
     #[inline(always)] pub fn dim(self) -> Styled<D> { self.style(Style::Dim) }
+    #[inline(always)] pub fn italic(self) -> Styled<D> { self.style(Style::Italic) }
     #[inline(always)] pub fn underlined(self) -> Styled<D> { self.style(Style::Underlined) }
     #[inline(always)] pub fn blink(self) -> Styled<D> { self.style(Style::Blink) }
     #[inline(always)] pub fn reverse(self) -> Styled<D> { self.style(Style::Reverse) }
     #[inline(always)] pub fn hidden(self) -> Styled<D> { self.style(Style::Hidden) }
+    #[inline(always)] pub fn strikethrough(self) -> Styled<D> { self.style(Style::Strikethrough) }
 }
 
 macro_rules! impl_fmt {
@@ -173,16 +397,16 @@ macro_rules! impl_fmt {
         impl<D: fmt::$name> fmt::$name for Styled<D> {
             fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
                 let mut reset = false;
-                if self.force.unwrap_or_else(should_style) {
+                if self.force.unwrap_or_else(|| self.stream.should_style()) {
                     if let Some(fg) = self.fg {
-                        write!(f, "\x1b[{}m", fg.ansi_num() + 30)?;
+                        fg.fmt_fg(f)?;
                         reset = true;
                     }
                     if let Some(bg) = self.bg {
-                        write!(f, "\x1b[{}m", bg.ansi_num() + 40)?;
+                        bg.fmt_bg(f)?;
                         reset = true;
                     }
-                    for style in &self.styles {
+                    for style in self.styles.iter() {
                         write!(f, "\x1b[{}m", style.ansi_num())?;
                         reset = true;
                     }
@@ -206,3 +430,252 @@ impl_fmt!(Octal);
 impl_fmt!(Pointer);
 impl_fmt!(UpperExp);
 impl_fmt!(UpperHex);
+
+/// An iterator over ANSI-escaped text.
+///
+/// Each item is either a matched ANSI escape sequence
+/// (`current_slice_is_ansi() == true`) or a slice of plain text.
+/// An `ESC` that is not followed by `[`, and a `CSI` sequence that ends
+/// before its final byte, are also yielded as escape slices.
+/// Concatenating the yielded slices in order reproduces the input string
+/// exactly.
+pub struct AnsiCodeIterator<'s> {
+    s: &'s str,
+    chars: CharIndices<'s>,
+    cur_is_ansi: bool,
+}
+
+impl<'s> AnsiCodeIterator<'s> {
+    /// Creates a new `AnsiCodeIterator` over the given string.
+    pub fn new(s: &'s str) -> AnsiCodeIterator<'s> {
+        AnsiCodeIterator {
+            s: s,
+            chars: s.char_indices(),
+            cur_is_ansi: false,
+        }
+    }
+
+    /// Returns `true` if the last returned slice was an ANSI escape.
+    pub fn current_slice_is_ansi(&self) -> bool {
+        self.cur_is_ansi
+    }
+}
+
+impl<'s> Iterator for AnsiCodeIterator<'s> {
+    type Item = &'s str;
+
+    fn next(&mut self) -> Option<&'s str> {
+        let s = self.s;
+        let start = match self.chars.clone().next() {
+            Some((idx, _)) => idx,
+            None => return None,
+        };
+
+        if s[start..].starts_with('\x1b') {
+            let mut end = start + 1;
+            let mut rest = self.chars.clone();
+            rest.next();
+            if let Some((_, '[')) = rest.next() {
+                end += 1;
+                while let Some((idx, c)) = rest.next() {
+                    end = idx + c.len_utf8();
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            self.cur_is_ansi = true;
+            for _ in s[start..end].chars() {
+                self.chars.next();
+            }
+            Some(&s[start..end])
+        } else {
+            let mut end = start;
+            loop {
+                let mut peek = self.chars.clone();
+                match peek.next() {
+                    Some((idx, c)) => {
+                        if c == '\x1b' {
+                            break;
+                        }
+                        end = idx + c.len_utf8();
+                        self.chars.next();
+                    }
+                    None => break,
+                }
+            }
+            self.cur_is_ansi = false;
+            Some(&s[start..end])
+        }
+    }
+}
+
+/// Strips ANSI escape codes from a string and returns the result.
+///
+/// This is useful for calculating the "real" length of a string when it
+/// may contain terminal styling codes.
+pub fn strip_ansi_codes(s: &str) -> Cow<'_, str> {
+    let mut stripped = String::new();
+    let mut changed = false;
+    for segment in AnsiCodeIterator::new(s) {
+        if segment.starts_with('\x1b') {
+            changed = true;
+        } else {
+            stripped.push_str(segment);
+        }
+    }
+    if changed {
+        Cow::Owned(stripped)
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// Measures the visible width of a string in terminal columns.
+///
+/// ANSI escape codes are stripped before measuring, and each character's
+/// display width is summed (wide CJK characters count as 2, zero-width
+/// combining marks count as 0).
+pub fn measure_text_width(s: &str) -> usize {
+    let stripped = strip_ansi_codes(s);
+    stripped
+        .chars()
+        .map(|c| UnicodeWidthChar::width(c).unwrap_or(0))
+        .sum()
+}
+
+/// Truncates a string to a maximum visible width, appending `tail`.
+///
+/// Any ANSI styling left open at the cut point is re-terminated with a
+/// trailing `\x1b[0m` so color never bleeds past the truncation.
+pub fn truncate_str<'s>(s: &'s str, max_width: usize, tail: &str) -> Cow<'s, str> {
+    if measure_text_width(s) <= max_width {
+        return Cow::Borrowed(s);
+    }
+
+    let tail_width = measure_text_width(tail);
+    let budget = max_width.saturating_sub(tail_width);
+
+    let mut out = String::new();
+    let mut width = 0;
+    let mut in_style = false;
+
+    for segment in AnsiCodeIterator::new(s) {
+        if segment.starts_with('\x1b') {
+            out.push_str(segment);
+            in_style = segment != "\x1b[0m";
+            continue;
+        }
+
+        for c in segment.chars() {
+            let w = UnicodeWidthChar::width(c).unwrap_or(0);
+            if width + w > budget {
+                out.push_str(tail);
+                if in_style {
+                    out.push_str("\x1b[0m");
+                }
+                return Cow::Owned(out);
+            }
+            width += w;
+            out.push(c);
+        }
+    }
+
+    out.push_str(tail);
+    if in_style {
+        out.push_str("\x1b[0m");
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_styled_emits_sgr_attributes_in_ascending_bit_order() {
+        let styled = style("x")
+            .strikethrough()
+            .bold()
+            .underlined()
+            .italic()
+            .force_styling(true);
+        assert_eq!(
+            format!("{}", styled),
+            "\x1b[1m\x1b[3m\x1b[4m\x1b[9mx\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_ansi_code_iterator_splits_escapes_and_text() {
+        let s = "\x1b[31mhello\x1b[0m world";
+        let parts: Vec<&str> = AnsiCodeIterator::new(s).collect();
+        assert_eq!(parts, vec!["\x1b[31m", "hello", "\x1b[0m", " world"]);
+        assert_eq!(parts.concat(), s);
+    }
+
+    #[test]
+    fn test_ansi_code_iterator_non_alphabetic_final_byte() {
+        // `@` (insert character, ICH) is a valid CSI final byte even
+        // though it isn't alphabetic.
+        let s = "\x1b[3@text";
+        let parts: Vec<&str> = AnsiCodeIterator::new(s).collect();
+        assert_eq!(parts, vec!["\x1b[3@", "text"]);
+    }
+
+    #[test]
+    fn test_ansi_code_iterator_incomplete_trailing_escape() {
+        let s = "hello\x1b[31";
+        let parts: Vec<&str> = AnsiCodeIterator::new(s).collect();
+        assert_eq!(parts.concat(), s);
+    }
+
+    #[test]
+    fn test_strip_ansi_codes() {
+        assert_eq!(strip_ansi_codes("\x1b[31mhello\x1b[0m"), "hello");
+        assert_eq!(strip_ansi_codes("plain"), "plain");
+    }
+
+    #[test]
+    fn test_strip_ansi_codes_non_alphabetic_final_byte() {
+        assert_eq!(strip_ansi_codes("\x1b[3@text"), "text");
+    }
+
+    #[test]
+    fn test_measure_text_width_ascii() {
+        assert_eq!(measure_text_width("hello"), 5);
+        assert_eq!(measure_text_width("\x1b[31mhello\x1b[0m"), 5);
+    }
+
+    #[test]
+    fn test_measure_text_width_wide_and_zero_width() {
+        // CJK characters are double-width.
+        assert_eq!(measure_text_width("\u{4f60}\u{597d}"), 4);
+        // Combining acute accent is zero-width.
+        assert_eq!(measure_text_width("e\u{0301}"), 1);
+    }
+
+    #[test]
+    fn test_truncate_str_no_op_when_within_budget() {
+        assert_eq!(truncate_str("hello", 10, "..."), "hello");
+    }
+
+    #[test]
+    fn test_truncate_str_cuts_to_budget() {
+        assert_eq!(truncate_str("hello world", 8, "..."), "hello...");
+    }
+
+    #[test]
+    fn test_truncate_str_reterminates_open_style() {
+        let s = "\x1b[31mhello world\x1b[0m";
+        let truncated = truncate_str(s, 8, "...");
+        assert_eq!(truncated, "\x1b[31mhello...\x1b[0m");
+    }
+
+    #[test]
+    fn test_truncate_str_respects_wide_chars() {
+        // Each CJK character is width 2, so only one fits before the tail.
+        let truncated = truncate_str("\u{4f60}\u{597d}\u{4e16}\u{754c}", 4, "..");
+        assert_eq!(truncated, "\u{4f60}..");
+    }
+}